@@ -0,0 +1,540 @@
+//! Geometry used by the tracer: rays, primitive intersection, reflection and
+//! refraction, and camera ray generation.
+//!
+//! Everything here is a pure function of its inputs so it can be tested
+//! without a window, a GPU or a scene.
+
+use nalgebra::{Matrix4, Vector2, Vector3};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ray {
+    pub origin: Vector3<f32>,
+    pub direction: Vector3<f32>,
+}
+
+impl Ray {
+    pub fn new(origin: Vector3<f32>, direction: Vector3<f32>) -> Self {
+        Self { origin, direction }
+    }
+
+    /// Point reached after travelling `t` along the ray.
+    pub fn at(&self, t: f32) -> Vector3<f32> {
+        self.origin + self.direction * t
+    }
+}
+
+/// Axis-aligned bounding box.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: Vector3<f32>, max: Vector3<f32>) -> Self {
+        Self { min, max }
+    }
+
+    /// Smallest box containing both `self` and `other`.
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+        }
+    }
+
+    /// Distance at which `ray` enters the box within `[t_min, t_max]`.
+    ///
+    /// Returns `t_min` when the ray starts inside the box.
+    pub fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<f32> {
+        let mut t_enter = t_min;
+        let mut t_exit = t_max;
+        for axis in 0..3 {
+            let inv_direction = 1.0 / ray.direction[axis];
+            let t0 = (self.min[axis] - ray.origin[axis]) * inv_direction;
+            let t1 = (self.max[axis] - ray.origin[axis]) * inv_direction;
+            let (near, far) = if inv_direction < 0.0 {
+                (t1, t0)
+            } else {
+                (t0, t1)
+            };
+            // f32::max/min drop NaN, which shows up when an axis-parallel
+            // ray starts exactly on a slab plane.
+            t_enter = t_enter.max(near);
+            t_exit = t_exit.min(far);
+            if t_exit < t_enter {
+                return None;
+            }
+        }
+        Some(t_enter)
+    }
+}
+
+/// Nearest distance in `(t_min, t_max)` at which `ray` hits the sphere.
+///
+/// The discriminant is computed from the closest-approach vector and the
+/// second root from the product of roots, so neither step cancels
+/// catastrophically for spheres that are large or far from the ray origin.
+pub fn intersect_sphere(
+    ray: &Ray,
+    center: Vector3<f32>,
+    radius: f32,
+    t_min: f32,
+    t_max: f32,
+) -> Option<f32> {
+    let oc = ray.origin - center;
+    let a = ray.direction.norm_squared();
+    let half_b = oc.dot(&ray.direction);
+    let c = oc.norm_squared() - radius * radius;
+
+    let closest = oc - ray.direction * (half_b / a);
+    let discriminant = a * (radius * radius - closest.norm_squared());
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    let q = -(half_b + half_b.signum() * discriminant.sqrt());
+    let (mut near, mut far) = (q / a, c / q);
+    if near > far {
+        std::mem::swap(&mut near, &mut far);
+    }
+    [near, far].into_iter().find(|t| *t > t_min && *t < t_max)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TriangleHit {
+    pub t: f32,
+    /// Barycentric weight of the second vertex.
+    pub u: f32,
+    /// Barycentric weight of the third vertex.
+    pub v: f32,
+}
+
+/// Two-sided Möller–Trumbore ray/triangle intersection within `(t_min, t_max)`.
+pub fn intersect_triangle(
+    ray: &Ray,
+    vertices: [Vector3<f32>; 3],
+    t_min: f32,
+    t_max: f32,
+) -> Option<TriangleHit> {
+    let [v0, v1, v2] = vertices;
+    let edge1 = v1 - v0;
+    let edge2 = v2 - v0;
+    let p = ray.direction.cross(&edge2);
+    let determinant = edge1.dot(&p);
+    if determinant == 0.0 {
+        return None;
+    }
+    let inv_determinant = 1.0 / determinant;
+
+    let s = ray.origin - v0;
+    let u = s.dot(&p) * inv_determinant;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let q = s.cross(&edge1);
+    let v = ray.direction.dot(&q) * inv_determinant;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = edge2.dot(&q) * inv_determinant;
+    (t > t_min && t < t_max).then_some(TriangleHit { t, u, v })
+}
+
+/// Mirror `incident` about `normal`. `normal` must be unit length.
+pub fn reflect(incident: Vector3<f32>, normal: Vector3<f32>) -> Vector3<f32> {
+    incident - normal * (2.0 * incident.dot(&normal))
+}
+
+/// Refract unit `incident` through a surface with unit `normal` facing
+/// against it, where `eta` is the ratio of the incident to the transmitted
+/// index of refraction.
+///
+/// Returns `None` on total internal reflection.
+pub fn refract(incident: Vector3<f32>, normal: Vector3<f32>, eta: f32) -> Option<Vector3<f32>> {
+    let cos_i = -incident.dot(&normal);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+    if sin2_t > 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(incident * eta + normal * (eta * cos_i - cos_t))
+}
+
+/// Schlick's approximation of the Fresnel reflectance for light arriving at
+/// `cos_i` to the normal, crossing from index `eta_i` into `eta_t`.
+///
+/// Returns 1 on total internal reflection.
+pub fn schlick(cos_i: f32, eta_i: f32, eta_t: f32) -> f32 {
+    let r0 = ((eta_i - eta_t) / (eta_i + eta_t)).powi(2);
+    let mut cos = cos_i;
+    // Leaving the denser medium, the curve is driven by the transmitted angle.
+    if eta_i > eta_t {
+        let eta = eta_i / eta_t;
+        let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+        if sin2_t > 1.0 {
+            return 1.0;
+        }
+        cos = (1.0 - sin2_t).sqrt();
+    }
+    r0 + (1.0 - r0) * (1.0 - cos).powi(5)
+}
+
+/// Map a position in pixel space (`0..size`, origin at the top-left) to
+/// normalized device coordinates (`-1..1`, y up).
+pub fn pixel_to_ndc(pixel: Vector2<f32>, size: Vector2<f32>) -> Vector2<f32> {
+    Vector2::new(pixel.x / size.x * 2.0 - 1.0, 1.0 - pixel.y / size.y * 2.0)
+}
+
+/// Texture coordinates (`0..1`, v down from the zenith) of unit `direction`
+/// in an equirectangular environment map rotated by `rotation` radians about
+/// the y axis. The `-z` direction maps to the center of the image.
+pub fn equirect_uv(direction: Vector3<f32>, rotation: f32) -> Vector2<f32> {
+    let phi = direction.x.atan2(-direction.z) + rotation;
+    let theta = direction.y.clamp(-1.0, 1.0).acos();
+    Vector2::new(
+        (phi / std::f32::consts::TAU + 0.5).rem_euclid(1.0),
+        theta / std::f32::consts::PI,
+    )
+}
+
+/// World-space direction of the primary ray through `ndc`, using the
+/// camera's cached inverse projection and inverse view matrices.
+pub fn camera_ray_direction(
+    inverse_projection: &Matrix4<f32>,
+    inverse_view: &Matrix4<f32>,
+    ndc: Vector2<f32>,
+) -> Vector3<f32> {
+    let target = inverse_projection * nalgebra::Vector4::new(ndc.x, ndc.y, 1.0, 1.0);
+    let view_direction = (target.xyz() / target.w).normalize();
+    (inverse_view * view_direction.push(0.0)).xyz().normalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Point3;
+    use proptest::prelude::*;
+
+    fn close(a: Vector3<f32>, b: Vector3<f32>, epsilon: f32) -> bool {
+        (a - b).norm() < epsilon
+    }
+
+    /// Nearest positive root of the textbook quadratic, solved in f64 from
+    /// the same f32 inputs.
+    fn intersect_sphere_f64(ray: &Ray, center: Vector3<f32>, radius: f32) -> Option<f64> {
+        let oc = ray.origin.cast::<f64>() - center.cast::<f64>();
+        let direction = ray.direction.cast::<f64>();
+        let radius = radius as f64;
+        let a = direction.norm_squared();
+        let half_b = oc.dot(&direction);
+        let c = oc.norm_squared() - radius * radius;
+        let discriminant = half_b * half_b - a * c;
+        if discriminant < 0.0 {
+            return None;
+        }
+        let sqrt = discriminant.sqrt();
+        [(-half_b - sqrt) / a, (-half_b + sqrt) / a]
+            .into_iter()
+            .find(|t| *t > 0.0)
+    }
+
+    fn unit_vector() -> impl Strategy<Value = Vector3<f32>> {
+        (-1.0f32..1.0, -1.0f32..1.0, -1.0f32..1.0)
+            .prop_filter("non-degenerate", |(x, y, z)| x * x + y * y + z * z > 1e-2)
+            .prop_map(|(x, y, z)| Vector3::new(x, y, z).normalize())
+    }
+
+    #[test]
+    fn sphere_hit_from_outside() {
+        let ray = Ray::new(Vector3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0));
+        let t = intersect_sphere(&ray, Vector3::zeros(), 1.0, 0.0, f32::MAX).unwrap();
+        assert!((t - 4.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn sphere_hit_from_inside_returns_exit() {
+        let ray = Ray::new(Vector3::zeros(), Vector3::new(1.0, 0.0, 0.0));
+        let t = intersect_sphere(&ray, Vector3::zeros(), 2.0, 0.0, f32::MAX).unwrap();
+        assert!((t - 2.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn sphere_miss_and_behind() {
+        let ray = Ray::new(Vector3::new(0.0, 2.0, 5.0), Vector3::new(0.0, 0.0, -1.0));
+        assert_eq!(
+            intersect_sphere(&ray, Vector3::zeros(), 1.0, 0.0, f32::MAX),
+            None
+        );
+
+        let ray = Ray::new(Vector3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 0.0, 1.0));
+        assert_eq!(
+            intersect_sphere(&ray, Vector3::zeros(), 1.0, 0.0, f32::MAX),
+            None
+        );
+    }
+
+    #[test]
+    fn sphere_precision_far_from_origin() {
+        let ray = Ray::new(Vector3::zeros(), Vector3::new(0.0, 0.0, -1.0));
+        let center = Vector3::new(0.0, 0.0, -1.0e5);
+        let t = intersect_sphere(&ray, center, 1.0, 0.0, f32::MAX).unwrap();
+        assert!((t - 99_999.0).abs() < 1e-2, "t = {t}");
+    }
+
+    #[test]
+    fn sphere_large_ground_at_grazing_angle() {
+        let ray = Ray::new(Vector3::zeros(), Vector3::new(1.0, -0.2, 0.0).normalize());
+        let center = Vector3::new(0.0, -101.0, 0.0);
+        let t = intersect_sphere(&ray, center, 100.0, 0.0, f32::MAX).unwrap();
+        assert!(((ray.at(t) - center).norm() - 100.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn triangle_hit_reports_barycentrics() {
+        let vertices = [
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+        ];
+        let ray = Ray::new(Vector3::new(0.25, 0.5, 1.0), Vector3::new(0.0, 0.0, -1.0));
+        let hit = intersect_triangle(&ray, vertices, 0.0, f32::MAX).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-6);
+        assert!((hit.u - 0.25).abs() < 1e-6);
+        assert!((hit.v - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn triangle_hit_tiny_triangle() {
+        let vertices = [
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(1e-4, 0.0, 0.0),
+            Vector3::new(0.0, 1e-4, 0.0),
+        ];
+        let ray = Ray::new(
+            Vector3::new(2.5e-5, 2.5e-5, 1.0),
+            Vector3::new(0.0, 0.0, -1.0),
+        );
+        let hit = intersect_triangle(&ray, vertices, 0.0, f32::MAX).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-6);
+        assert!((hit.u - 0.25).abs() < 1e-4);
+        assert!((hit.v - 0.25).abs() < 1e-4);
+    }
+
+    #[test]
+    fn triangle_misses() {
+        let vertices = [
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+        ];
+        let outside = Ray::new(Vector3::new(0.8, 0.8, 1.0), Vector3::new(0.0, 0.0, -1.0));
+        assert_eq!(intersect_triangle(&outside, vertices, 0.0, f32::MAX), None);
+
+        let parallel = Ray::new(Vector3::new(0.2, 0.2, 1.0), Vector3::new(1.0, 0.0, 0.0));
+        assert_eq!(intersect_triangle(&parallel, vertices, 0.0, f32::MAX), None);
+
+        let behind = Ray::new(Vector3::new(0.2, 0.2, 1.0), Vector3::new(0.0, 0.0, 1.0));
+        assert_eq!(intersect_triangle(&behind, vertices, 0.0, f32::MAX), None);
+    }
+
+    #[test]
+    fn aabb_intersections() {
+        let aabb = Aabb::new(Vector3::new(-1.0, -1.0, -1.0), Vector3::new(1.0, 1.0, 1.0));
+
+        let outside = Ray::new(Vector3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0));
+        assert_eq!(aabb.intersect(&outside, 0.0, f32::MAX), Some(4.0));
+        assert_eq!(aabb.intersect(&outside, 0.0, 3.0), None);
+
+        let inside = Ray::new(Vector3::zeros(), Vector3::new(0.0, 1.0, 0.0));
+        assert_eq!(aabb.intersect(&inside, 0.0, f32::MAX), Some(0.0));
+
+        let miss = Ray::new(Vector3::new(2.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0));
+        assert_eq!(aabb.intersect(&miss, 0.0, f32::MAX), None);
+
+        let on_slab = Ray::new(Vector3::new(1.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0));
+        assert_eq!(aabb.intersect(&on_slab, 0.0, f32::MAX), Some(4.0));
+    }
+
+    #[test]
+    fn aabb_union() {
+        let a = Aabb::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 1.0));
+        let b = Aabb::new(Vector3::new(-1.0, 0.5, 0.5), Vector3::new(0.5, 2.0, 0.5));
+        let union = a.union(&b);
+        assert_eq!(union.min, Vector3::new(-1.0, 0.0, 0.0));
+        assert_eq!(union.max, Vector3::new(1.0, 2.0, 1.0));
+    }
+
+    #[test]
+    fn refract_normal_incidence_is_unchanged() {
+        let incident = Vector3::new(0.0, -1.0, 0.0);
+        let normal = Vector3::new(0.0, 1.0, 0.0);
+        let refracted = refract(incident, normal, 1.0 / 1.5).unwrap();
+        assert!(close(refracted, incident, 1e-6), "{refracted:?}");
+    }
+
+    #[test]
+    fn refract_total_internal_reflection() {
+        let incident = Vector3::new(1.0, -0.1, 0.0).normalize();
+        let normal = Vector3::new(0.0, 1.0, 0.0);
+        assert_eq!(refract(incident, normal, 1.5), None);
+    }
+
+    #[test]
+    fn schlick_limits() {
+        assert!((schlick(1.0, 1.0, 1.5) - 0.04).abs() < 1e-6);
+        assert!((schlick(1.0, 1.5, 1.0) - 0.04).abs() < 1e-6);
+        assert!((schlick(0.0, 1.0, 1.5) - 1.0).abs() < 1e-6);
+        assert_eq!(schlick(0.1, 1.5, 1.0), 1.0);
+        assert!(schlick(0.5, 1.0, 1.5) > schlick(0.9, 1.0, 1.5));
+    }
+
+    #[test]
+    fn pixel_to_ndc_corners() {
+        let size = Vector2::new(200.0, 100.0);
+        assert_eq!(
+            pixel_to_ndc(Vector2::new(0.0, 0.0), size),
+            Vector2::new(-1.0, 1.0)
+        );
+        assert_eq!(pixel_to_ndc(size, size), Vector2::new(1.0, -1.0));
+        assert_eq!(pixel_to_ndc(size / 2.0, size), Vector2::zeros());
+    }
+
+    #[test]
+    fn equirect_uv_axes_and_rotation() {
+        let uv = |x, y, z, rotation| equirect_uv(Vector3::new(x, y, z), rotation);
+        assert!((uv(0.0, 0.0, -1.0, 0.0) - Vector2::new(0.5, 0.5)).norm() < 1e-6);
+        assert!((uv(1.0, 0.0, 0.0, 0.0) - Vector2::new(0.75, 0.5)).norm() < 1e-6);
+        assert!((uv(0.0, 1.0, 0.0, 0.0).y).abs() < 1e-6);
+        assert!((uv(0.0, -1.0, 0.0, 0.0).y - 1.0).abs() < 1e-6);
+        let rotated = uv(1.0, 0.0, 0.0, std::f32::consts::FRAC_PI_2);
+        assert!(rotated.x.min(1.0 - rotated.x) < 1e-6);
+    }
+
+    #[test]
+    fn camera_center_ray_points_forward() {
+        let eye = Point3::new(1.0, 2.0, 3.0);
+        let target = Point3::new(0.0, 0.0, 0.0);
+        let view = Matrix4::look_at_rh(&eye, &target, &Vector3::y());
+        let projection = Matrix4::new_perspective(16.0 / 9.0, 45f32.to_radians(), 0.1, 100.0);
+        let direction = camera_ray_direction(
+            &projection.try_inverse().unwrap(),
+            &view.try_inverse().unwrap(),
+            Vector2::zeros(),
+        );
+        assert!(
+            close(direction, (target - eye).normalize(), 1e-5),
+            "{direction:?}"
+        );
+    }
+
+    #[test]
+    fn camera_edge_ray_matches_field_of_view() {
+        let fov = 60f32.to_radians();
+        let projection = Matrix4::new_perspective(1.0, fov, 0.1, 100.0);
+        let direction = camera_ray_direction(
+            &projection.try_inverse().unwrap(),
+            &Matrix4::identity(),
+            Vector2::new(0.0, 1.0),
+        );
+        let angle = direction.angle(&Vector3::new(0.0, 0.0, -1.0));
+        assert!((angle - fov / 2.0).abs() < 1e-5);
+        assert!(direction.y > 0.0);
+    }
+
+    proptest! {
+        #[test]
+        fn sphere_hit_matches_f64_reference(
+            direction in unit_vector(),
+            side in unit_vector(),
+            center in (-10.0f32..10.0, -10.0f32..10.0, -10.0f32..10.0),
+            radius in 0.1f32..5.0,
+            distance in 10.0f32..1000.0,
+            miss_fraction in 0.0f32..0.9,
+        ) {
+            let center = Vector3::new(center.0, center.1, center.2);
+            let lateral = side - direction * side.dot(&direction);
+            prop_assume!(lateral.norm() > 1e-2);
+            let aim = center + lateral.normalize() * (radius * miss_fraction);
+            let ray = Ray::new(aim - direction * distance, direction);
+
+            let t = intersect_sphere(&ray, center, radius, 0.0, f32::MAX)
+                .ok_or_else(|| TestCaseError::fail("ray missed the sphere"))?;
+            let reference = intersect_sphere_f64(&ray, center, radius)
+                .ok_or_else(|| TestCaseError::fail("reference missed the sphere"))?;
+            prop_assert!(
+                (t as f64 - reference).abs() <= 4.0 * f32::EPSILON as f64 * reference,
+                "t = {t}, reference = {reference}"
+            );
+        }
+
+        #[test]
+        fn triangle_hit_matches_barycentric_point(
+            u in 0.01f32..0.98,
+            v_fraction in 0.01f32..0.99,
+            offset in unit_vector(),
+            side in prop_oneof![Just(1.0f32), Just(-1.0f32)],
+        ) {
+            let vertices = [
+                Vector3::new(-1.0, -1.0, 0.0),
+                Vector3::new(2.0, -0.5, 0.5),
+                Vector3::new(0.0, 1.5, -0.5),
+            ];
+            let normal = (vertices[1] - vertices[0])
+                .cross(&(vertices[2] - vertices[0]))
+                .normalize();
+            let v = (1.0 - u) * v_fraction;
+            let point = vertices[0] * (1.0 - u - v) + vertices[1] * u + vertices[2] * v;
+            // Keep the ray well away from grazing, where precision is
+            // legitimately poor, while still hitting both faces.
+            let origin = point + normal * (3.0 * side) + offset * 2.0;
+            let ray = Ray::new(origin, (point - origin).normalize());
+            let hit = intersect_triangle(&ray, vertices, 0.0, f32::MAX)
+                .ok_or_else(|| TestCaseError::fail("ray missed the triangle"))?;
+            prop_assert!(close(ray.at(hit.t), point, 1e-4), "{:?} != {point:?}", ray.at(hit.t));
+            prop_assert!((hit.u - u).abs() < 1e-4 && (hit.v - v).abs() < 1e-4);
+        }
+
+        #[test]
+        fn reflect_preserves_length_and_is_involution(
+            incident in unit_vector(),
+            normal in unit_vector(),
+        ) {
+            let reflected = reflect(incident, normal);
+            prop_assert!((reflected.norm() - 1.0).abs() < 1e-5);
+            let twice = reflect(reflected, normal);
+            prop_assert!(close(twice, incident, 1e-5), "{twice:?} != {incident:?}");
+        }
+
+        #[test]
+        fn refract_is_unit_length_and_obeys_snell(
+            incident in unit_vector(),
+            eta in 0.5f32..2.0,
+        ) {
+            let normal = Vector3::new(0.0, 1.0, 0.0);
+            let incident = if incident.y > 0.0 { -incident } else { incident };
+            if let Some(refracted) = refract(incident, normal, eta) {
+                prop_assert!((refracted.norm() - 1.0).abs() < 1e-4);
+                let sin_i = incident.cross(&normal).norm();
+                let sin_t = refracted.cross(&normal).norm();
+                prop_assert!((sin_t - eta * sin_i).abs() < 1e-4);
+            }
+        }
+
+        #[test]
+        fn refract_is_reversible(incident in unit_vector(), eta in 0.5f32..2.0) {
+            let normal = Vector3::new(0.0, 1.0, 0.0);
+            let incident = if incident.y > 0.0 { -incident } else { incident };
+            // At grazing incidence rounding can tip the way back into total
+            // internal reflection.
+            prop_assume!(incident.y.abs() > 1e-2);
+            if let Some(refracted) = refract(incident, normal, eta) {
+                let back = refract(-refracted, -normal, 1.0 / eta)
+                    .ok_or_else(|| TestCaseError::fail("total internal reflection on the way back"))?;
+                prop_assert!(close(back, -incident, 1e-3), "{back:?} != {:?}", -incident);
+            }
+        }
+    }
+}